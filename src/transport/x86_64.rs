@@ -11,10 +11,11 @@ use super::{
     DeviceStatus, DeviceType, Transport,
 };
 use crate::{
-    hal::{Hal, PhysAddr},
+    hal::{BufferDirection, Hal, PhysAddr},
     Error,
 };
 use core::arch::asm;
+use core::marker::PhantomData;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// This CPUID returns the signature and should be used to determine if VM is running under pKVM,
@@ -26,46 +27,149 @@ const KVM_CPUID_SIGNATURE: u32 = 0x40000000;
 const KVM_HC_PKVM_OP: u32 = 20;
 const PKVM_GHC_IOREAD: u32 = KVM_HC_PKVM_OP + 3;
 const PKVM_GHC_IOWRITE: u32 = KVM_HC_PKVM_OP + 4;
+const PKVM_GHC_IO_BATCH: u32 = KVM_HC_PKVM_OP + 5;
 
 const PKVM_SIGNATURE: &[u8] = b"PKVM";
 
-/// The maximum number of bytes that can be read or written by a single IO hypercall.
-const HYP_IO_MAX: usize = 8;
+/// Feature bit in the `KVM_CPUID_SIGNATURE + 1` (`KVM_CPUID_FEATURES`) leaf's `eax` indicating
+/// that the hypervisor supports the `PKVM_GHC_IO_BATCH` hypercall.
+const PKVM_FEATURE_IO_BATCH: u32 = 1 << 0;
+
+/// The maximum number of entries that can be passed to a single `PKVM_GHC_IO_BATCH` hypercall.
+const MAX_BATCH_ENTRIES: usize = 8;
+
+/// PCI capability ID for MSI-X, from the PCI Local Bus specification.
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// Offset of the Table Offset/BIR field within an MSI-X capability structure.
+const MSIX_CAP_TABLE_OFFSET: u8 = 4;
+
+/// Mask for the BAR indicator bits within the MSI-X Table Offset/BIR field.
+const MSIX_BIR_MASK: u32 = 0x7;
+
+/// The size in bytes of a single entry in the MSI-X table.
+const MSIX_TABLE_ENTRY_SIZE: usize = 16;
+
+/// Message Control bit 15: MSI-X Enable.
+const MSIX_MESSAGE_CONTROL_ENABLE: u16 = 1 << 15;
+
+/// Message Control bit 14: Function Mask. While set, all vectors are masked regardless of their
+/// per-vector mask bit.
+const MSIX_MESSAGE_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+
+/// `cfg_type` for `VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`, from the VirtIO spec.
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+
+/// Offset of the high dword of the 64-bit offset field within a shared memory capability, which
+/// follows the base `virtio_pci_cap` fields.
+const SHARED_MEMORY_CAP_OFFSET_HI_OFFSET: u8 = 16;
+
+/// Offset of the high dword of the 64-bit length field within a shared memory capability, which
+/// follows the base `virtio_pci_cap` fields.
+const SHARED_MEMORY_CAP_LENGTH_HI_OFFSET: u8 = 20;
+
+/// The maximum number of distinct shared memory regions (keyed by `id`) that this transport can
+/// track.
+const MAX_SHARED_MEMORY_REGIONS: usize = 8;
+
+/// A hypercall-based backend for accessing a region of physical IO address space.
+///
+/// This abstracts over the hypervisor-specific calling convention used to trap IO accesses out to
+/// the host, so that [`HypCam`], [`HypPciTransport`] and [`HypIoRegion`] can be reused by other
+/// architectures and hypervisor backends without duplicating the PCI capability-parsing and
+/// `Transport` logic.
+pub trait HypIo {
+    /// The maximum number of bytes that can be read or written by a single IO access.
+    const HYP_IO_MAX: usize;
+
+    /// Asks the hypervisor to perform an IO read at the given physical address.
+    fn io_read(addr: usize, size: usize) -> u64;
+
+    /// Asks the hypervisor to perform an IO write at the given physical address.
+    fn io_write(addr: usize, size: usize, data: u64);
+
+    /// Performs a batch of `(addr, size, data)` IO writes, replaying them in order.
+    ///
+    /// Backends that support a dedicated multi-op hypercall need a guest-physical buffer that the
+    /// host can read the batch from, so this takes a `Hal` to allocate and share one; the default
+    /// implementation doesn't need it and just issues one [`HypIo::io_write`] hypercall per entry.
+    /// Backends with a dedicated multi-op hypercall should override this to issue a single trap
+    /// instead, falling back to this behaviour when the hypervisor doesn't support it.
+    fn io_write_batch<H: Hal>(ops: &[(usize, usize, u64)]) {
+        for &(addr, size, data) in ops {
+            Self::io_write(addr, size, data);
+        }
+    }
+}
+
+/// The [`HypIo`] backend for the x86-64 pKVM hypervisor, using the `vmcall` instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PkvmHypIo;
+
+impl HypIo for PkvmHypIo {
+    const HYP_IO_MAX: usize = 8;
+
+    fn io_read(addr: usize, size: usize) -> u64 {
+        hyp_io_read(addr, size)
+    }
+
+    fn io_write(addr: usize, size: usize, data: u64) {
+        hyp_io_write(addr, size, data)
+    }
+
+    fn io_write_batch<H: Hal>(ops: &[(usize, usize, u64)]) {
+        if ops.len() <= MAX_BATCH_ENTRIES && io_batch_supported() {
+            hyp_io_write_batch::<H>(ops);
+        } else {
+            for &(addr, size, data) in ops {
+                hyp_io_write(addr, size, data);
+            }
+        }
+    }
+}
 
-/// A PCI configuration access mechanism using hypercalls implemented by the x86-64 pKVM hypervisor.
-pub struct HypCam {
+/// A PCI configuration access mechanism using hypercalls implemented by a pKVM-like hypervisor.
+pub struct HypCam<T: HypIo = PkvmHypIo> {
     /// The physical base address of the PCI root complex.
     phys_base: usize,
     cam: Cam,
+    _io: PhantomData<T>,
 }
 
-impl HypCam {
+impl<T: HypIo> HypCam<T> {
     /// Creates a new `HypCam` for the PCI root complex at the given physical base address.
     pub fn new(phys_base: usize, cam: Cam) -> Self {
-        Self { phys_base, cam }
+        Self {
+            phys_base,
+            cam,
+            _io: PhantomData,
+        }
     }
+}
 
+impl HypCam<PkvmHypIo> {
     /// Returns whether we are running under pKVM by checking the CPU ID signature.
     pub fn is_pkvm() -> bool {
         cpuid_signature() == PKVM_SIGNATURE
     }
 }
 
-impl ConfigurationAccess for HypCam {
+impl<T: HypIo> ConfigurationAccess for HypCam<T> {
     fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
         let address = self.cam.cam_offset(device_function, register_offset);
-        hyp_io_read(self.phys_base + (address as usize), 4) as u32
+        T::io_read(self.phys_base + (address as usize), 4) as u32
     }
 
     fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
         let address = self.cam.cam_offset(device_function, register_offset);
-        hyp_io_write(self.phys_base + (address as usize), 4, data.into());
+        T::io_write(self.phys_base + (address as usize), 4, data.into());
     }
 
     unsafe fn unsafe_clone(&self) -> Self {
         Self {
             phys_base: self.phys_base,
             cam: self.cam,
+            _io: PhantomData,
         }
     }
 }
@@ -82,27 +186,83 @@ macro_rules! configwrite {
     };
 }
 
-/// PCI transport for VirtIO using hypercalls implemented by the x86-64 pKVM hypervisor for IO BARs.
-#[derive(Debug)]
-pub struct HypPciTransport {
+/// Builds a `(offset, size, value)` entry for [`HypIoRegion::write_batch`] from a `CommonCfg`
+/// field name and value.
+macro_rules! batch_entry {
+    ($field:ident, $value:expr) => {{
+        let value = $value;
+        (
+            core::mem::offset_of!(CommonCfg, $field),
+            size_of_val(&value),
+            u64::from(value),
+        )
+    }};
+}
+
+/// Bit 0 of the ISR status register: a virtqueue has used a buffer.
+const ISR_QUEUE_INTERRUPT: u8 = 0x1;
+/// Bit 1 of the ISR status register: the device configuration has changed.
+const ISR_CONFIG_INTERRUPT: u8 = 0x2;
+
+/// The interrupt conditions reported by the ISR status register, per the VirtIO spec.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+pub struct InterruptStatus {
+    /// Whether one or more virtqueues have used a buffer, i.e. there is a virtqueue interrupt
+    /// pending.
+    pub used_buffer: bool,
+    /// Whether the device configuration space has changed, i.e. `read_config_generation` and the
+    /// device config space should be re-read.
+    pub config_changed: bool,
+}
+
+/// PCI transport for VirtIO using hypercalls implemented by a pKVM-like hypervisor for IO BARs.
+///
+/// `H` is used to allocate the guest-physical, host-visible buffer that batched IO writes (see
+/// [`HypIoRegion::write_batch`]) are staged through.
+pub struct HypPciTransport<H: Hal, T: HypIo = PkvmHypIo> {
     device_type: DeviceType,
     /// The bus, device and function identifier for the VirtIO device.
     device_function: DeviceFunction,
     /// The common configuration structure within some BAR.
-    common_cfg: HypIoRegion,
+    common_cfg: HypIoRegion<T>,
     /// The start of the queue notification region within some BAR.
-    notify_region: HypIoRegion,
+    notify_region: HypIoRegion<T>,
     notify_off_multiplier: u32,
     /// The ISR status register within some BAR.
-    isr_status: HypIoRegion,
+    isr_status: HypIoRegion<T>,
     /// The VirtIO device-specific configuration within some BAR.
-    config_space: Option<HypIoRegion>,
+    config_space: Option<HypIoRegion<T>>,
+    /// The MSI-X table within some BAR, if the device supports MSI-X.
+    msix_table: Option<HypIoRegion<T>>,
+    /// The number of entries in the MSI-X table, i.e. the number of vectors the device supports.
+    msix_table_size: u16,
+    /// VirtIO shared memory regions (`VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`), indexed by `id`. These
+    /// are mapped directly rather than accessed via hypercalls.
+    shared_memory_regions: [Option<(PhysAddr, usize)>; MAX_SHARED_MEMORY_REGIONS],
+    _hal: PhantomData<H>,
 }
 
-impl HypPciTransport {
-    /// Constructs a new x86-64 pKVM PCI VirtIO transport for the given device function on the given
-    /// PCI root controller.
-    pub fn new<H: Hal, C: ConfigurationAccess>(
+impl<H: Hal, T: HypIo> core::fmt::Debug for HypPciTransport<H, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("HypPciTransport")
+            .field("device_type", &self.device_type)
+            .field("device_function", &self.device_function)
+            .field("common_cfg", &self.common_cfg)
+            .field("notify_region", &self.notify_region)
+            .field("notify_off_multiplier", &self.notify_off_multiplier)
+            .field("isr_status", &self.isr_status)
+            .field("config_space", &self.config_space)
+            .field("msix_table", &self.msix_table)
+            .field("msix_table_size", &self.msix_table_size)
+            .field("shared_memory_regions", &self.shared_memory_regions)
+            .finish()
+    }
+}
+
+impl<H: Hal, T: HypIo> HypPciTransport<H, T> {
+    /// Constructs a new pKVM-style PCI VirtIO transport for the given device function on the
+    /// given PCI root controller.
+    pub fn new<C: ConfigurationAccess>(
         root: &mut PciRoot<C>,
         device_function: DeviceFunction,
     ) -> Result<Self, VirtioPciError> {
@@ -120,7 +280,44 @@ impl HypPciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut msix_cap = None;
+        let mut msix_table_size = 0;
+        let mut shared_memory_caps: [Option<(u32, u32, VirtioCapabilityInfo)>;
+            MAX_SHARED_MEMORY_REGIONS] = [None; MAX_SHARED_MEMORY_REGIONS];
         for capability in root.capabilities(device_function) {
+            if capability.id == PCI_CAP_ID_MSIX {
+                // The Message Control field shares the capability's `private_header`; the table
+                // size is encoded in its low 11 bits (0-based).
+                let message_control = capability.private_header;
+                let table_size = (message_control & 0x7ff) + 1;
+                let table_offset_bir = root
+                    .configuration_access
+                    .read_word(device_function, capability.offset + MSIX_CAP_TABLE_OFFSET);
+                msix_cap = Some(VirtioCapabilityInfo {
+                    bar: (table_offset_bir & MSIX_BIR_MASK) as u8,
+                    offset: table_offset_bir & !MSIX_BIR_MASK,
+                    length: u32::from(table_size) * MSIX_TABLE_ENTRY_SIZE as u32,
+                });
+                msix_table_size = table_size;
+
+                // Set the MSI-X Enable bit and clear the Function Mask bit, so that the vectors
+                // we are about to program actually deliver interrupts. The enable/mask bits live
+                // in the upper half of the same word as the capability ID and next-pointer, so
+                // re-read it fresh rather than reassembling it from `capability.id`.
+                let cap_header = root
+                    .configuration_access
+                    .read_word(device_function, capability.offset);
+                let new_message_control = (message_control | MSIX_MESSAGE_CONTROL_ENABLE)
+                    & !MSIX_MESSAGE_CONTROL_FUNCTION_MASK;
+                let new_cap_header =
+                    (cap_header & 0x0000_ffff) | (u32::from(new_message_control) << 16);
+                root.configuration_access.write_word(
+                    device_function,
+                    capability.offset,
+                    new_cap_header,
+                );
+                continue;
+            }
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
             }
@@ -159,11 +356,63 @@ impl HypPciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG => {
+                    // The `id` field reuses the padding byte that immediately follows `bar`.
+                    let bar_and_id = root
+                        .configuration_access
+                        .read_word(device_function, capability.offset + CAP_BAR_OFFSET);
+                    let id = (bar_and_id >> 8) as u8;
+                    let offset_hi = root.configuration_access.read_word(
+                        device_function,
+                        capability.offset + SHARED_MEMORY_CAP_OFFSET_HI_OFFSET,
+                    );
+                    let length_hi = root.configuration_access.read_word(
+                        device_function,
+                        capability.offset + SHARED_MEMORY_CAP_LENGTH_HI_OFFSET,
+                    );
+                    // `root.bar_info` takes `&mut self`, which we can't call while `capability`
+                    // still borrows the capability iterator, so just stash what we've parsed and
+                    // resolve the BAR once the loop (and its borrow of `root`) has ended.
+                    //
+                    // `id` is a full byte per the VirtIO spec, but we only track the first
+                    // `MAX_SHARED_MEMORY_REGIONS` of them; devices that expose more are expected to
+                    // be rare, and `shared_memory_region` documents the cap, so regions beyond it
+                    // are intentionally dropped here rather than treated as an error.
+                    if let Some(slot) = shared_memory_caps.get_mut(usize::from(id)) {
+                        *slot = Some((offset_hi, length_hi, struct_info));
+                    }
+                }
                 _ => {}
             }
         }
 
-        let common_cfg = get_bar_region::<H, CommonCfg, _>(
+        let mut shared_memory_regions = [None; MAX_SHARED_MEMORY_REGIONS];
+        for (id, cap) in shared_memory_caps.iter().enumerate() {
+            let Some((offset_hi, length_hi, struct_info)) = cap else {
+                continue;
+            };
+            let Ok(bar_info) = root.bar_info(device_function, struct_info.bar) else {
+                continue;
+            };
+            let Some((bar_address, bar_size)) = bar_info.memory_address_size() else {
+                continue;
+            };
+            let region_offset = u64::from(*offset_hi) << 32 | u64::from(struct_info.offset);
+            let region_length = u64::from(*length_hi) << 32 | u64::from(struct_info.length);
+            // A malformed or malicious device could report an offset/length pair that overflows
+            // when added; treat that the same as a region that doesn't fit the BAR.
+            let Some(region_end) = region_offset.checked_add(region_length) else {
+                continue;
+            };
+            if bar_address != 0 && region_end <= bar_size {
+                shared_memory_regions[id] = Some((
+                    bar_address as PhysAddr + region_offset as PhysAddr,
+                    region_length as usize,
+                ));
+            }
+        }
+
+        let common_cfg = get_bar_region::<H, T, CommonCfg, _>(
             root,
             device_function,
             &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
@@ -175,16 +424,16 @@ impl HypPciTransport {
                 notify_off_multiplier,
             ));
         }
-        let notify_region = get_bar_region::<H, u16, _>(root, device_function, &notify_cfg)?;
+        let notify_region = get_bar_region::<H, T, u16, _>(root, device_function, &notify_cfg)?;
 
-        let isr_status = get_bar_region::<H, u8, _>(
+        let isr_status = get_bar_region::<H, T, u8, _>(
             root,
             device_function,
             &isr_cfg.ok_or(VirtioPciError::MissingIsrConfig)?,
         )?;
 
         let config_space = if let Some(device_cfg) = device_cfg {
-            Some(get_bar_region::<H, u32, _>(
+            Some(get_bar_region::<H, T, u32, _>(
                 root,
                 device_function,
                 &device_cfg,
@@ -193,6 +442,16 @@ impl HypPciTransport {
             None
         };
 
+        let msix_table = if let Some(msix_cap) = msix_cap {
+            Some(get_bar_region::<H, T, u32, _>(
+                root,
+                device_function,
+                &msix_cap,
+            )?)
+        } else {
+            None
+        };
+
         Ok(Self {
             device_type,
             device_function,
@@ -201,15 +460,24 @@ impl HypPciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            msix_table,
+            msix_table_size,
+            shared_memory_regions,
+            _hal: PhantomData,
         })
     }
 }
 
-impl Transport for HypPciTransport {
+impl<H: Hal, T: HypIo> Transport for HypPciTransport<H, T> {
     fn device_type(&self) -> DeviceType {
         self.device_type
     }
 
+    // This stays as four serialized accesses rather than a `read_batch`: `PKVM_GHC_IO_BATCH`
+    // only carries a `value` to write per entry (see `HypIoBatchEntry`), with no channel for the
+    // hypervisor to return per-entry read results, so there's no single hypercall to batch these
+    // into. Feature negotiation also happens once per device, so the extra round trips aren't on
+    // a hot path the way `queue_set` is.
     fn read_device_features(&mut self) -> u64 {
         configwrite!(self.common_cfg, device_feature_select, 0u32);
         let device_features_low: u32 = configread!(self.common_cfg, device_feature);
@@ -219,14 +487,12 @@ impl Transport for HypPciTransport {
     }
 
     fn write_driver_features(&mut self, driver_features: u64) {
-        configwrite!(self.common_cfg, driver_feature_select, 0u32);
-        configwrite!(self.common_cfg, driver_feature, driver_features as u32);
-        configwrite!(self.common_cfg, driver_feature_select, 1u32);
-        configwrite!(
-            self.common_cfg,
-            driver_feature,
-            (driver_features >> 32) as u32
-        );
+        self.common_cfg.write_batch::<H, _>([
+            batch_entry!(driver_feature_select, 0u32),
+            batch_entry!(driver_feature, driver_features as u32),
+            batch_entry!(driver_feature_select, 1u32),
+            batch_entry!(driver_feature, (driver_features >> 32) as u32),
+        ]);
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -269,12 +535,14 @@ impl Transport for HypPciTransport {
         driver_area: PhysAddr,
         device_area: PhysAddr,
     ) {
-        configwrite!(self.common_cfg, queue_select, queue);
-        configwrite!(self.common_cfg, queue_size, size as u16);
-        configwrite!(self.common_cfg, queue_desc, descriptors as u64);
-        configwrite!(self.common_cfg, queue_driver, driver_area as u64);
-        configwrite!(self.common_cfg, queue_device, device_area as u64);
-        configwrite!(self.common_cfg, queue_enable, 1u16);
+        self.common_cfg.write_batch::<H, _>([
+            batch_entry!(queue_select, queue),
+            batch_entry!(queue_size, size as u16),
+            batch_entry!(queue_desc, descriptors as u64),
+            batch_entry!(queue_driver, driver_area as u64),
+            batch_entry!(queue_device, device_area as u64),
+            batch_entry!(queue_enable, 1u16),
+        ]);
     }
 
     fn queue_unset(&mut self, _queue: u16) {
@@ -289,44 +557,40 @@ impl Transport for HypPciTransport {
     }
 
     fn ack_interrupt(&mut self) -> bool {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
-        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
-        let isr_status: u8 = self.isr_status.read(0);
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        let status = self.ack_interrupt_status();
+        status.used_buffer || status.config_changed
     }
 
     fn read_config_generation(&self) -> u32 {
         configread!(self.common_cfg, config_generation)
     }
 
-    fn read_config_space<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
-        assert!(align_of::<T>() <= 4,
+    fn read_config_space<V: FromBytes>(&self, offset: usize) -> Result<V, Error> {
+        assert!(align_of::<V>() <= 4,
             "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-            align_of::<T>());
-        assert_eq!(offset % align_of::<T>(), 0);
+            align_of::<V>());
+        assert_eq!(offset % align_of::<V>(), 0);
 
         let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
-        if config_space.size < offset + size_of::<T>() {
+        if config_space.size < offset + size_of::<V>() {
             Err(Error::ConfigSpaceTooSmall)
         } else {
             Ok(config_space.read(offset))
         }
     }
 
-    fn write_config_space<T: IntoBytes + Immutable>(
+    fn write_config_space<V: IntoBytes + Immutable>(
         &mut self,
         offset: usize,
-        value: T,
+        value: V,
     ) -> Result<(), Error> {
-        assert!(align_of::<T>() <= 4,
+        assert!(align_of::<V>() <= 4,
             "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-            align_of::<T>());
-        assert_eq!(offset % align_of::<T>(), 0);
+            align_of::<V>());
+        assert_eq!(offset % align_of::<V>(), 0);
 
         let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
-        if config_space.size < offset + size_of::<T>() {
+        if config_space.size < offset + size_of::<V>() {
             Err(Error::ConfigSpaceTooSmall)
         } else {
             config_space.write(offset, value);
@@ -335,11 +599,95 @@ impl Transport for HypPciTransport {
     }
 }
 
-fn get_bar_region<H: Hal, T, C: ConfigurationAccess>(
+impl<H: Hal, T: HypIo> HypPciTransport<H, T> {
+    /// Routes the given virtqueue's interrupts to the given MSI-X vector.
+    ///
+    /// Returns whether the device accepted the vector; the device writes back `0xffff` if it
+    /// doesn't support as many MSI-X vectors as requested.
+    pub fn set_queue_vector(&mut self, queue: u16, vector: u16) -> bool {
+        configwrite!(self.common_cfg, queue_select, queue);
+        configwrite!(self.common_cfg, queue_msix_vector, vector);
+        let assigned_vector: u16 = configread!(self.common_cfg, queue_msix_vector);
+        assigned_vector == vector
+    }
+
+    /// Routes device configuration change interrupts to the given MSI-X vector.
+    ///
+    /// Returns whether the device accepted the vector; the device writes back `0xffff` if it
+    /// doesn't support as many MSI-X vectors as requested.
+    pub fn set_config_vector(&mut self, vector: u16) -> bool {
+        configwrite!(self.common_cfg, msix_config, vector);
+        let assigned_vector: u16 = configread!(self.common_cfg, msix_config);
+        assigned_vector == vector
+    }
+
+    /// Reads and clears the ISR status register, returning which interrupt conditions it reported.
+    ///
+    /// Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt, so
+    /// this should be called at most once per interrupt; use this instead of [`Transport::ack_interrupt`]
+    /// when the caller wants to distinguish a virtqueue interrupt from a configuration-change one,
+    /// e.g. to decide whether to re-read the device config space.
+    pub fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        let isr_status: u8 = self.isr_status.read(0);
+        InterruptStatus {
+            used_buffer: isr_status & ISR_QUEUE_INTERRUPT != 0,
+            config_changed: isr_status & ISR_CONFIG_INTERRUPT != 0,
+        }
+    }
+
+    /// Returns the guest-physical base address and size in bytes of the shared memory region with
+    /// the given `id`, if the device exposes one (`VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`).
+    ///
+    /// Shared memory regions (e.g. the virtio-fs DAX window, or virtio-gpu host-visible memory)
+    /// are mapped directly into a BAR rather than being accessed via IO hypercalls, so the caller
+    /// is responsible for mapping the returned range itself.
+    ///
+    /// This is exposed as an inherent method, not a [`Transport`] one, because only this PCI
+    /// transport (not e.g. the MMIO transport) has a BAR to map these regions into; ideally this
+    /// would still be a defaulted `Transport` method so code generic over `T: Transport` could
+    /// reach it, but `Transport` isn't defined in this file and this tree doesn't have the rest of
+    /// the crate to add it to.
+    ///
+    /// Only the first `MAX_SHARED_MEMORY_REGIONS` ids are tracked; a device exposing more has its
+    /// extra regions silently dropped during capability parsing rather than treated as an error.
+    pub fn shared_memory_region(&self, id: u8) -> Option<(PhysAddr, usize)> {
+        self.shared_memory_regions
+            .get(usize::from(id))
+            .copied()
+            .flatten()
+    }
+
+    /// Writes the given entry of the MSI-X table, if the device exposes one.
+    ///
+    /// Returns false if the device doesn't support MSI-X, or if `vector` is not a valid index into
+    /// its table.
+    pub fn write_msix_table_entry(
+        &mut self,
+        vector: u16,
+        addr: u64,
+        data: u32,
+        masked: bool,
+    ) -> bool {
+        let Some(msix_table) = self.msix_table else {
+            return false;
+        };
+        if vector >= self.msix_table_size {
+            return false;
+        }
+        let entry_offset = usize::from(vector) * MSIX_TABLE_ENTRY_SIZE;
+        msix_table.write(entry_offset, addr as u32);
+        msix_table.write(entry_offset + 4, (addr >> 32) as u32);
+        msix_table.write(entry_offset + 8, data);
+        msix_table.write(entry_offset + 12, u32::from(masked));
+        true
+    }
+}
+
+fn get_bar_region<H: Hal, T: HypIo, V, C: ConfigurationAccess>(
     root: &mut PciRoot<C>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
-) -> Result<HypIoRegion, VirtioPciError> {
+) -> Result<HypIoRegion<T>, VirtioPciError> {
     let bar_info = root.bar_info(device_function, struct_info.bar)?;
     let (bar_address, bar_size) = bar_info
         .memory_address_size()
@@ -348,23 +696,47 @@ fn get_bar_region<H: Hal, T, C: ConfigurationAccess>(
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
     if struct_info.offset + struct_info.length > bar_size
-        || size_of::<T>() > struct_info.length as usize
+        || size_of::<V>() > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
     }
     let paddr = bar_address as PhysAddr + struct_info.offset as PhysAddr;
-    if paddr % align_of::<T>() != 0 {
+    if paddr % align_of::<V>() != 0 {
         return Err(VirtioPciError::Misaligned {
             address: paddr,
-            alignment: align_of::<T>(),
+            alignment: align_of::<V>(),
         });
     }
     Ok(HypIoRegion {
         paddr,
         size: struct_info.length as usize,
+        _io: PhantomData,
     })
 }
 
+/// Returns whether the hypervisor advertises support for the `PKVM_GHC_IO_BATCH` hypercall.
+fn io_batch_supported() -> bool {
+    cpuid_features() & PKVM_FEATURE_IO_BATCH != 0
+}
+
+/// Gets the feature bits from the `KVM_CPUID_FEATURES` leaf.
+fn cpuid_features() -> u32 {
+    let features: u32;
+    unsafe {
+        // See the comment on `cpuid_signature` for why rbx needs to be saved and restored here.
+        asm!(
+            "push rbx",
+            "cpuid",
+            "pop rbx",
+            in("eax") KVM_CPUID_SIGNATURE + 1,
+            lateout("eax") features,
+            out("rcx") _,
+            out("rdx") _,
+        );
+    }
+    features
+}
+
 /// Gets the signature CPU ID.
 fn cpuid_signature() -> [u8; 4] {
     let signature: u32;
@@ -435,30 +807,127 @@ fn hyp_io_write(address: usize, size: usize, data: u64) {
     }
 }
 
+/// A single entry of a `PKVM_GHC_IO_BATCH` request: an IO write of `value` of `size` bytes at
+/// physical address `addr`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, FromBytes, IntoBytes, Immutable)]
+struct HypIoBatchEntry {
+    addr: u64,
+    size: u64,
+    value: u64,
+}
+
+/// Asks the hypervisor to perform the given IO writes in a single hypercall.
+///
+/// `ops` must contain at most `MAX_BATCH_ENTRIES` entries. The hypervisor reads the batch by
+/// guest-physical address, so the entries are written into a one-page DMA buffer allocated and
+/// shared via `H`, rather than a private stack buffer the host has no way to translate.
+fn hyp_io_write_batch<H: Hal>(ops: &[(usize, usize, u64)]) {
+    debug_assert!(ops.len() <= MAX_BATCH_ENTRIES);
+    let (paddr, vaddr) = H::dma_alloc(1, BufferDirection::DriverToDevice);
+    let entries = vaddr.cast::<HypIoBatchEntry>();
+    for (i, &(addr, size, value)) in ops.iter().enumerate() {
+        // Safe because `entries` points to a freshly allocated page which is large enough for
+        // `MAX_BATCH_ENTRIES` entries, and we only write within `ops.len() <= MAX_BATCH_ENTRIES`.
+        unsafe {
+            entries.as_ptr().add(i).write(HypIoBatchEntry {
+                addr: addr as u64,
+                size: size as u64,
+                value,
+            });
+        }
+    }
+    hyp_io_batch(paddr, ops.len());
+    // Safe because `paddr`/`vaddr` are the pair just returned by `dma_alloc` and the hypervisor
+    // has finished reading the buffer by the time `hyp_io_batch` returns.
+    unsafe {
+        H::dma_dealloc(paddr, vaddr, 1);
+    }
+}
+
+/// Asks the hypervisor to replay `count` IO writes pointed to by the guest-physical address
+/// `entries_paddr`, which must point to `count` consecutive [`HypIoBatchEntry`] records.
+fn hyp_io_batch(entries_paddr: PhysAddr, count: usize) {
+    unsafe {
+        // See the comment on `hyp_io_write` for why rbx needs to be saved and restored here.
+        asm!(
+            "push rbx",
+            "mov rbx, r8",
+            "vmcall",
+            "pop rbx",
+            in("rax") PKVM_GHC_IO_BATCH,
+            in("r8") entries_paddr,
+            in("rcx") count,
+        );
+    }
+}
+
 /// A region of physical address space which may be accessed by IO read and/or write hypercalls.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-struct HypIoRegion {
+struct HypIoRegion<T: HypIo = PkvmHypIo> {
     /// The physical address of the start of the IO region.
     paddr: usize,
     /// The size of the IO region in bytes.
     size: usize,
+    _io: PhantomData<T>,
 }
 
-impl HypIoRegion {
-    fn read<T: FromBytes>(self, offset: usize) -> T {
-        assert!(offset + size_of::<T>() <= self.size);
-        assert!(size_of::<T>() < HYP_IO_MAX);
+impl<T: HypIo> Clone for HypIoRegion<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: HypIo> Copy for HypIoRegion<T> {}
+
+impl<T: HypIo> Eq for HypIoRegion<T> {}
+
+impl<T: HypIo> PartialEq for HypIoRegion<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.paddr == other.paddr && self.size == other.size
+    }
+}
 
-        let data = hyp_io_read(self.paddr + offset, size_of::<T>());
-        T::read_from_prefix(data.as_bytes()).unwrap().0
+impl<T: HypIo> core::fmt::Debug for HypIoRegion<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("HypIoRegion")
+            .field("paddr", &self.paddr)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl<T: HypIo> HypIoRegion<T> {
+    fn read<V: FromBytes>(self, offset: usize) -> V {
+        assert!(offset + size_of::<V>() <= self.size);
+        assert!(size_of::<V>() <= T::HYP_IO_MAX);
+
+        let data = T::io_read(self.paddr + offset, size_of::<V>());
+        V::read_from_prefix(data.as_bytes()).unwrap().0
     }
 
-    fn write<T: IntoBytes + Immutable>(self, offset: usize, value: T) {
-        assert!(offset + size_of::<T>() <= self.size);
-        assert!(size_of::<T>() < HYP_IO_MAX);
+    fn write<V: IntoBytes + Immutable>(self, offset: usize, value: V) {
+        assert!(offset + size_of::<V>() <= self.size);
+        assert!(size_of::<V>() <= T::HYP_IO_MAX);
 
         let mut data = 0;
-        data.as_mut_bytes()[..size_of::<T>()].copy_from_slice(value.as_bytes());
-        hyp_io_write(self.paddr + offset, size_of::<T>(), data);
+        data.as_mut_bytes()[..size_of::<V>()].copy_from_slice(value.as_bytes());
+        T::io_write(self.paddr + offset, size_of::<V>(), data);
+    }
+
+    /// Writes several `(offset, size, value)` fields within this region through the backend's
+    /// [`HypIo::io_write_batch`], preserving their relative order. For [`PkvmHypIo`] that is a
+    /// single hypercall when the hypervisor supports batching and there are at most
+    /// `MAX_BATCH_ENTRIES` fields; otherwise it is one hypercall per field.
+    ///
+    /// `H` is used to allocate the guest-physical buffer backends with a dedicated batch
+    /// hypercall stage the writes through; see [`HypIo::io_write_batch`].
+    fn write_batch<H: Hal, const N: usize>(self, ops: [(usize, usize, u64); N]) {
+        let mut absolute = [(0usize, 0usize, 0u64); N];
+        for (entry, &(offset, size, value)) in absolute.iter_mut().zip(ops.iter()) {
+            assert!(offset + size <= self.size);
+            assert!(size <= T::HYP_IO_MAX);
+            *entry = (self.paddr + offset, size, value);
+        }
+        T::io_write_batch::<H>(&absolute);
     }
 }